@@ -17,59 +17,274 @@
 //! Bridging round state between rounds.
 
 use round::State as RoundState;
-use futures::task;
-use parking_lot::{RwLock, RwLockReadGuard};
+use futures::{Async, Future, Poll, task};
+use parking_lot::{Mutex, RwLock, RwLockReadGuard};
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 // round state bridged across rounds.
 struct Bridged<H> {
 	inner: RwLock<RoundState<H>>,
-	task: task::AtomicTask,
+	// bumped on every `PriorView::update`, so that a `LatterView` can tell
+	// whether it has missed an update without relying on an edge-triggered
+	// notification alone.
+	version: AtomicUsize,
+	// set once this round has been pruned from its `RoundStateTracker`, so
+	// that futures built from a `LatterView` onto it resolve instead of
+	// waiting forever on a round that will never update again.
+	pruned: AtomicBool,
+	next_id: AtomicUsize,
+	tasks: Mutex<HashMap<usize, task::AtomicTask>>,
 }
 
 impl<H> Bridged<H> {
 	fn new(inner: RwLock<RoundState<H>>) -> Self {
 		Bridged {
 			inner,
-			task: task::AtomicTask::new(),
+			version: AtomicUsize::new(0),
+			pruned: AtomicBool::new(false),
+			next_id: AtomicUsize::new(0),
+			tasks: Mutex::new(HashMap::new()),
 		}
 	}
+
+	// register a new waiting slot, returning its id.
+	fn register(&self) -> usize {
+		let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+		self.tasks.lock().insert(id, task::AtomicTask::new());
+		id
+	}
+
+	// drop a waiting slot.
+	fn deregister(&self, id: usize) {
+		self.tasks.lock().remove(&id);
+	}
+
+	// notify every registered waiting slot.
+	fn notify_all(&self) {
+		for task in self.tasks.lock().values() {
+			task.notify();
+		}
+	}
+
+	fn is_pruned(&self) -> bool {
+		self.pruned.load(Ordering::Acquire)
+	}
+
+	// mark this round as pruned and wake every waiter so it can resolve.
+	fn prune(&self) {
+		self.pruned.store(true, Ordering::Release);
+		self.notify_all();
+	}
 }
 
 /// A prior view of a round-state.
 pub(crate) struct PriorView<H>(Arc<Bridged<H>>);
 
 impl<H> PriorView<H> {
-	/// Push an update to the latter view.
+	/// Push an update to all latter views.
 	pub(crate) fn update(&self, new: RoundState<H>) {
 		*self.0.inner.write() = new;
-		self.0.task.notify();
+		self.0.version.fetch_add(1, Ordering::Release);
+		self.0.notify_all();
 	}
 }
 
 /// A latter view of a round-state.
-pub(crate) struct LatterView<H>(Arc<Bridged<H>>);
+///
+/// Can be cloned to give another, independent handle onto the same
+/// round-state: each clone registers its own waiting slot and deregisters it
+/// on drop, so a dropped view's slot is never left dangling.
+pub(crate) struct LatterView<H> {
+	bridge: Arc<Bridged<H>>,
+	id: usize,
+	// the bridge's version as of the last time this view observed a change
+	// via `changed()`.
+	last_seen: AtomicUsize,
+}
 
 impl<H> LatterView<H> {
-	/// Fetch a handle to the last round-state.
+	fn new(bridge: Arc<Bridged<H>>) -> Self {
+		let id = bridge.register();
+		let last_seen = AtomicUsize::new(bridge.version.load(Ordering::Acquire));
+		LatterView { bridge, id, last_seen }
+	}
+
+	/// Fetch a handle to the last round-state. This is always available and
+	/// never blocks waiting for an update.
 	pub(crate) fn get(&self) -> RwLockReadGuard<RoundState<H>> {
-		self.0.task.register();
-		self.0.inner.read()
+		self.register_task();
+		self.bridge.inner.read()
+	}
+
+	/// A future that resolves the next time the round-state changes, i.e.
+	/// the bridge's version advances past the one this view last observed.
+	/// Unlike `get`, this cannot miss an update that happens between two
+	/// calls: each resolution simply records the version it saw.
+	///
+	/// Takes `&mut self`, like `watch::Receiver::changed`: the returned
+	/// future borrows this view for as long as it's outstanding, so the
+	/// borrow checker rules out two `Changed` futures racing to consume the
+	/// same update off one handle. Call `clone()` on the view first if more
+	/// than one independent waiter is needed.
+	pub(crate) fn changed(&mut self) -> Changed<H> {
+		Changed { view: self }
+	}
+
+	fn register_task(&self) {
+		if let Some(task) = self.bridge.tasks.lock().get(&self.id) {
+			task.register();
+		}
+	}
+
+	/// A future that resolves once the round-state satisfies the given
+	/// predicate, which is checked against the current state immediately and
+	/// again after every subsequent update. This is the common pattern of
+	/// "block until the prior round is completable" or similar, without each
+	/// caller hand-rolling its own register/read/poll loop.
+	pub(crate) fn wait_for<F>(&self, pred: F) -> WaitFor<H, F>
+		where F: Fn(&RoundState<H>) -> bool
+	{
+		WaitFor { view: self.clone(), pred }
+	}
+}
+
+/// Future returned by `LatterView::wait_for`.
+pub(crate) struct WaitFor<H, F> {
+	view: LatterView<H>,
+	pred: F,
+}
+
+impl<H, F: Fn(&RoundState<H>) -> bool> Future for WaitFor<H, F> {
+	type Item = ();
+	type Error = ();
+
+	fn poll(&mut self) -> Poll<(), ()> {
+		let matches = (self.pred)(&*self.view.get());
+		if matches || self.view.bridge.is_pruned() {
+			Ok(Async::Ready(()))
+		} else {
+			Ok(Async::NotReady)
+		}
+	}
+}
+
+/// Future returned by `LatterView::changed`.
+pub(crate) struct Changed<'a, H: 'a> {
+	view: &'a mut LatterView<H>,
+}
+
+impl<'a, H> Future for Changed<'a, H> {
+	type Item = ();
+	type Error = ();
+
+	fn poll(&mut self) -> Poll<(), ()> {
+		if let Some(task) = self.view.bridge.tasks.lock().get(&self.view.id) {
+			task.register();
+		}
+
+		if self.view.bridge.is_pruned() {
+			return Ok(Async::Ready(()));
+		}
+
+		let current = self.view.bridge.version.load(Ordering::Acquire);
+		let last = self.view.last_seen.swap(current, Ordering::AcqRel);
+		if current != last {
+			Ok(Async::Ready(()))
+		} else {
+			Ok(Async::NotReady)
+		}
+	}
+}
+
+impl<H> Clone for LatterView<H> {
+	fn clone(&self) -> Self {
+		LatterView::new(self.bridge.clone())
+	}
+}
+
+impl<H> Drop for LatterView<H> {
+	fn drop(&mut self) {
+		self.bridge.deregister(self.id);
 	}
 }
 
 /// Constructs two views of a bridged round-state.
 ///
 /// The prior view is held by a round which produces the state and pushes updates to a latter view.
-/// When updating, the latter view's task is updated.
+/// When updating, every latter view's task is notified.
 ///
 /// The latter view is held by the subsequent round, which blocks certain activity
-/// while waiting for events on an older round.
+/// while waiting for events on an older round. It may be cloned to hand out to
+/// several independent waiters.
 pub(crate) fn bridge_state<H>(initial: RoundState<H>) -> (PriorView<H>, LatterView<H>) {
-	let inner = Arc::new(Bridged::new(RwLock::new(initial)));
-	(
-		PriorView(inner.clone()), LatterView(inner)
-	)
+	let bridge = Arc::new(Bridged::new(RwLock::new(initial)));
+	let latter = LatterView::new(bridge.clone());
+	(PriorView(bridge), latter)
+}
+
+/// Tracks the bridged round-state of every round that is still live, keyed by
+/// round number.
+///
+/// Round `N` begins tracking with `begin_round`, which hands back a
+/// `PriorView` it uses to publish its state as the round progresses. Any
+/// later round can acquire a `LatterView` onto round `N`, or any earlier
+/// round still held by the tracker, via `view`. This generalizes
+/// `bridge_state`'s single prior/latter pair to the whole set of rounds that
+/// might still be waited on, since GRANDPA may need to wait on the
+/// completability or estimate of a round several steps back when higher
+/// rounds stall.
+pub(crate) struct RoundStateTracker<H> {
+	rounds: RwLock<BTreeMap<u64, Arc<Bridged<H>>>>,
+}
+
+impl<H> RoundStateTracker<H> {
+	/// Create a new, empty tracker.
+	pub(crate) fn new() -> Self {
+		RoundStateTracker { rounds: RwLock::new(BTreeMap::new()) }
+	}
+
+	/// Begin tracking `round_number`, returning a `PriorView` that the round
+	/// should use to publish its state as it progresses.
+	///
+	/// Panics if `round_number` is already tracked: round numbers are the
+	/// tracker's whole identity contract, and silently replacing an entry
+	/// would orphan any `PriorView`/`LatterView` already handed out for the
+	/// old one, disconnecting it from the tracker (and from updates) for good.
+	pub(crate) fn begin_round(&self, round_number: u64, initial: RoundState<H>) -> PriorView<H> {
+		let bridge = Arc::new(Bridged::new(RwLock::new(initial)));
+		let mut rounds = self.rounds.write();
+		assert!(
+			!rounds.contains_key(&round_number),
+			"round {} is already tracked", round_number,
+		);
+		rounds.insert(round_number, bridge.clone());
+		PriorView(bridge)
+	}
+
+	/// Acquire a `LatterView` onto `round_number`. Returns `None` if that
+	/// round has never been begun, or has since been pruned.
+	pub(crate) fn view(&self, round_number: u64) -> Option<LatterView<H>> {
+		self.rounds.read().get(&round_number).cloned().map(LatterView::new)
+	}
+
+	/// Drop all tracked rounds at or below `finalized_round`, since a round's
+	/// bridged state is no longer needed once a later round has finalized
+	/// past it. Any `LatterView`, `changed()`, or `wait_for()` future already
+	/// acquired onto a pruned round resolves cleanly rather than dangling:
+	/// `changed()` resolves immediately and `wait_for()` resolves regardless
+	/// of whether its predicate ever holds. `get()` keeps returning the
+	/// round's last known state.
+	pub(crate) fn prune(&self, finalized_round: u64) {
+		let mut rounds = self.rounds.write();
+		let stale: Vec<u64> = rounds.range(..=finalized_round).map(|(&round, _)| round).collect();
+		for round in stale {
+			if let Some(bridge) = rounds.remove(&round) {
+				bridge.prune();
+			}
+		}
+	}
 }
 
 #[cfg(test)]
@@ -89,14 +304,70 @@ mod tests {
 		};
 
 		let (prior, latter) = bridge_state(initial);
-		let waits_for_finality = ::futures::future::poll_fn(move || -> Poll<(), ()> {
-			if latter.get().finalized.is_some() {
-				Ok(Async::Ready(()))
-			} else {
-				Ok(Async::NotReady)
-			}
+		let waits_for_finality = latter.wait_for(|state| state.finalized.is_some());
+
+		let barrier = Arc::new(Barrier::new(2));
+		let barrier_other = barrier.clone();
+		::std::thread::spawn(move || {
+			barrier_other.wait();
+			prior.update(RoundState {
+				prevote_ghost: Some(("5", 5)),
+				finalized: Some(("1", 1)),
+				estimate: Some(("3", 3)),
+				completable: true,
+			});
+		});
+
+		barrier.wait();
+		waits_for_finality.wait().unwrap();
+	}
+
+	#[test]
+	fn cloned_latter_views_are_independently_notified() {
+		let initial = RoundState {
+			prevote_ghost: None,
+			finalized: None,
+			estimate: None,
+			completable: false,
+		};
+
+		let (prior, latter_a) = bridge_state(initial);
+		let latter_b = latter_a.clone();
+
+		let waits_a = latter_a.wait_for(|state| state.finalized.is_some());
+		let waits_b = latter_b.wait_for(|state| state.finalized.is_some());
+
+		let barrier = Arc::new(Barrier::new(2));
+		let barrier_other = barrier.clone();
+		::std::thread::spawn(move || {
+			barrier_other.wait();
+			prior.update(RoundState {
+				prevote_ghost: Some(("5", 5)),
+				finalized: Some(("1", 1)),
+				estimate: Some(("3", 3)),
+				completable: true,
+			});
 		});
 
+		barrier.wait();
+		waits_a.wait().unwrap();
+		waits_b.wait().unwrap();
+	}
+
+	#[test]
+	fn changed_never_misses_an_update() {
+		let initial = RoundState {
+			prevote_ghost: None,
+			finalized: None,
+			estimate: None,
+			completable: false,
+		};
+
+		let (prior, mut latter) = bridge_state(initial);
+
+		// reading the current value never blocks, even before any update.
+		latter.wait_for(|state| state.finalized.is_none()).wait().unwrap();
+
 		let barrier = Arc::new(Barrier::new(2));
 		let barrier_other = barrier.clone();
 		::std::thread::spawn(move || {
@@ -110,6 +381,144 @@ mod tests {
 		});
 
 		barrier.wait();
+		latter.changed().wait().unwrap();
+		latter.wait_for(|state| state.finalized.is_some()).wait().unwrap();
+	}
+
+	#[test]
+	fn changed_tracks_per_handle_across_separate_awaits() {
+		let initial = RoundState {
+			prevote_ghost: None,
+			finalized: None,
+			estimate: None,
+			completable: false,
+		};
+
+		let (prior, mut latter) = bridge_state(initial);
+
+		prior.update(RoundState {
+			prevote_ghost: Some(("5", 5)),
+			finalized: Some(("1", 1)),
+			estimate: Some(("3", 3)),
+			completable: true,
+		});
+
+		// first await observes the update...
+		latter.changed().wait().unwrap();
+
+		let barrier = Arc::new(Barrier::new(2));
+		let barrier_other = barrier.clone();
+		::std::thread::spawn(move || {
+			barrier_other.wait();
+			prior.update(RoundState {
+				prevote_ghost: Some(("6", 6)),
+				finalized: Some(("2", 2)),
+				estimate: Some(("4", 4)),
+				completable: true,
+			});
+		});
+
+		barrier.wait();
+		// ...and a later, independent await on the same handle still sees the next one.
+		latter.changed().wait().unwrap();
+	}
+
+	#[test]
+	fn tracker_views_any_earlier_round() {
+		let tracker = RoundStateTracker::new();
+
+		let round_1 = tracker.begin_round(1, RoundState {
+			prevote_ghost: None,
+			finalized: None,
+			estimate: None,
+			completable: false,
+		});
+		let _round_2 = tracker.begin_round(2, RoundState {
+			prevote_ghost: None,
+			finalized: None,
+			estimate: None,
+			completable: false,
+		});
+
+		// round 3 waits on round 1, several rounds back.
+		let latter = tracker.view(1).expect("round 1 is live");
+		assert!(tracker.view(42).is_none());
+
+		round_1.update(RoundState {
+			prevote_ghost: Some(("5", 5)),
+			finalized: Some(("1", 1)),
+			estimate: Some(("3", 3)),
+			completable: true,
+		});
+
+		latter.wait_for(|state| state.finalized.is_some()).wait().unwrap();
+	}
+
+	#[test]
+	fn tracker_prunes_finalized_rounds() {
+		let tracker: RoundStateTracker<&'static str> = RoundStateTracker::new();
+		let no_state = || RoundState {
+			prevote_ghost: None,
+			finalized: None,
+			estimate: None,
+			completable: false,
+		};
+
+		let _round_1 = tracker.begin_round(1, no_state());
+		let _round_2 = tracker.begin_round(2, no_state());
+		let _round_3 = tracker.begin_round(3, no_state());
+
+		tracker.prune(2);
+
+		assert!(tracker.view(1).is_none());
+		assert!(tracker.view(2).is_none());
+		assert!(tracker.view(3).is_some());
+	}
+
+	#[test]
+	fn tracker_prune_wakes_views_acquired_before_it() {
+		let tracker: RoundStateTracker<&'static str> = RoundStateTracker::new();
+
+		let prior = tracker.begin_round(1, RoundState {
+			prevote_ghost: None,
+			finalized: None,
+			estimate: None,
+			completable: false,
+		});
+		let mut latter_changed = tracker.view(1).expect("round 1 is live");
+		let latter_wait = tracker.view(1).expect("round 1 is live");
+
+		// built while round 1 is still live, and never satisfied by any update.
+		let changed = latter_changed.changed();
+		let waits_for_finality = latter_wait.wait_for(|state| state.finalized.is_some());
+
+		let barrier = Arc::new(Barrier::new(2));
+		let barrier_other = barrier.clone();
+		::std::thread::spawn(move || {
+			barrier_other.wait();
+			tracker.prune(1);
+			drop(prior);
+		});
+
+		barrier.wait();
+
+		// both resolve once the round is pruned, instead of hanging forever.
+		changed.wait().unwrap();
 		waits_for_finality.wait().unwrap();
 	}
+
+	#[test]
+	#[should_panic(expected = "round 1 is already tracked")]
+	fn begin_round_panics_on_duplicate_round_number() {
+		let tracker: RoundStateTracker<&'static str> = RoundStateTracker::new();
+		let no_state = || RoundState {
+			prevote_ghost: None,
+			finalized: None,
+			estimate: None,
+			completable: false,
+		};
+
+		let _round_1 = tracker.begin_round(1, no_state());
+		tracker.begin_round(1, no_state());
+	}
 }